@@ -0,0 +1,194 @@
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::request_response::{ProtocolName, RequestResponseCodec};
+
+/// Maximum size of a single file transfer, to keep a misbehaving peer from
+/// forcing us to allocate an unbounded buffer.
+const MAX_FILE_SIZE: u32 = 100 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct FileExchangeProtocol;
+
+impl ProtocolName for FileExchangeProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/chakra/file-exchange/1.0.0"
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileRequest {
+    pub filename: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileResponse {
+    /// `false` means the peer could not serve the requested file; `bytes` is
+    /// then empty and must not be mistaken for an empty file.
+    pub found: bool,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetworkCodec;
+
+#[async_trait]
+impl RequestResponseCodec for NetworkCodec {
+    type Protocol = FileExchangeProtocol;
+    type Request = FileRequest;
+    type Response = FileResponse;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<FileRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let filename = read_length_prefixed(io, MAX_FILE_SIZE).await?;
+
+        Ok(FileRequest {
+            filename: String::from_utf8(filename)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+        })
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+    ) -> io::Result<FileResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut found_byte = [0u8; 1];
+        io.read_exact(&mut found_byte).await?;
+        let bytes = read_length_prefixed(io, MAX_FILE_SIZE).await?;
+
+        Ok(FileResponse { found: found_byte[0] != 0, bytes })
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        FileRequest { filename }: FileRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, filename.into_bytes()).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &FileExchangeProtocol,
+        io: &mut T,
+        FileResponse { found, bytes }: FileResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&[found as u8]).await?;
+        write_length_prefixed(io, bytes).await
+    }
+}
+
+async fn read_length_prefixed<T>(io: &mut T, max_size: u32) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut len_bytes = [0u8; 4];
+    io.read_exact(&mut len_bytes).await?;
+    let len = u32::from_be_bytes(len_bytes);
+
+    if len > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("file exchange payload of {} bytes exceeds the {} byte limit", len, max_size),
+        ));
+    }
+
+    let mut bytes = vec![0u8; len as usize];
+    io.read_exact(&mut bytes).await?;
+
+    Ok(bytes)
+}
+
+async fn write_length_prefixed<T>(io: &mut T, bytes: Vec<u8>) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+{
+    io.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
+    io.write_all(&bytes).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::io::{AsyncSeekExt, Cursor, SeekFrom};
+
+    use super::*;
+
+    #[test]
+    fn request_round_trips() {
+        block_on(async {
+            let mut codec = NetworkCodec::default();
+            let mut buf = Cursor::new(Vec::new());
+
+            codec
+                .write_request(
+                    &FileExchangeProtocol,
+                    &mut buf,
+                    FileRequest { filename: "notes.txt".to_owned() },
+                )
+                .await
+                .unwrap();
+
+            buf.seek(SeekFrom::Start(0)).await.unwrap();
+            let request = codec.read_request(&FileExchangeProtocol, &mut buf).await.unwrap();
+
+            assert_eq!(request.filename, "notes.txt");
+        });
+    }
+
+    #[test]
+    fn response_round_trips() {
+        block_on(async {
+            let mut codec = NetworkCodec::default();
+            let mut buf = Cursor::new(Vec::new());
+
+            codec
+                .write_response(
+                    &FileExchangeProtocol,
+                    &mut buf,
+                    FileResponse { found: true, bytes: b"hello".to_vec() },
+                )
+                .await
+                .unwrap();
+
+            buf.seek(SeekFrom::Start(0)).await.unwrap();
+            let response = codec.read_response(&FileExchangeProtocol, &mut buf).await.unwrap();
+
+            assert!(response.found);
+            assert_eq!(response.bytes, b"hello");
+        });
+    }
+
+    #[test]
+    fn rejects_payload_over_the_size_limit() {
+        block_on(async {
+            let mut oversized = (MAX_FILE_SIZE + 1).to_be_bytes().to_vec();
+            oversized.extend(std::iter::repeat(0).take(16));
+            let mut buf = Cursor::new(oversized);
+
+            let err = read_length_prefixed(&mut buf, MAX_FILE_SIZE).await.unwrap_err();
+
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        });
+    }
+}
@@ -1,33 +1,97 @@
-use std::{error::Error, str};
+mod file_exchange;
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    error::Error,
+    fs,
+    hash::{Hash, Hasher},
+    iter,
+    path::{Path, PathBuf},
+    str,
+    time::Duration,
+};
 
 use async_std::{
     io::{self, prelude::BufReadExt, stdout},
     process,
 };
+use clap::Parser;
 use futures::{select, AsyncWriteExt, StreamExt};
 use libp2p::{
-    floodsub::{Floodsub, FloodsubEvent, FloodsubMessage, Topic},
+    bandwidth::BandwidthLogging,
+    gossipsub::{
+        error::PublishError, Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage,
+        IdentTopic as Topic, MessageAuthenticity, MessageId, ValidationMode,
+    },
     identity,
-    ping::{Ping, PingConfig, PingEvent},
-    swarm::SwarmEvent,
-    Multiaddr, NetworkBehaviour, PeerId, Swarm,
+    mdns::{Mdns, MdnsConfig, MdnsEvent},
+    multiaddr::Protocol,
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    rendezvous,
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseEvent, RequestResponseMessage,
+    },
+    swarm::{ConnectionLimits, SwarmBuilder, SwarmEvent},
+    Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport,
 };
 use log::{error, info, warn};
 use termion::{color, style};
 
-const ADDRESS: &str = "/ip4/0.0.0.0/tcp/0";
+use file_exchange::{FileExchangeProtocol, FileRequest, FileResponse, NetworkCodec};
+
+const DEFAULT_LISTEN_ADDRESS: &str = "/ip4/0.0.0.0/tcp/0";
+const DEFAULT_TOPIC: &str = "chakra-chat";
+const DEFAULT_IDENTITY_PATH: &str = "chakra_key";
+const DOWNLOADS_DIR: &str = "downloads";
+const SHARED_DIR: &str = "shared";
+const RENDEZVOUS_DISCOVER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Command-line options for the Chakra chat node.
+#[derive(Parser, Debug)]
+#[command(name = "chakra", about = "A peer-to-peer chat node built on libp2p")]
+struct Args {
+    /// Multiaddr to listen on. Can be passed multiple times to bind multiple interfaces/ports.
+    #[arg(long = "listen")]
+    listen: Vec<Multiaddr>,
+
+    /// Gossipsub topic to chat on.
+    #[arg(long, default_value = DEFAULT_TOPIC)]
+    topic: String,
+
+    /// Maximum number of simultaneous established connections.
+    #[arg(long = "max-peers")]
+    max_peers: Option<u32>,
+
+    /// Path to the file storing this node's persisted identity keypair.
+    #[arg(long, default_value = DEFAULT_IDENTITY_PATH)]
+    identity: PathBuf,
+
+    /// Multiaddr of a rendezvous point to register with for discovery across NATs.
+    #[arg(long)]
+    rendezvous: Option<Multiaddr>,
+
+    /// Namespace to register/discover under at the rendezvous point.
+    #[arg(long, requires = "rendezvous")]
+    namespace: Option<String>,
+}
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "OutEvent")]
 struct MyBehaviour {
     ping: Ping,
-    floodsub: Floodsub,
+    gossipsub: Gossipsub,
+    mdns: Mdns,
+    request_response: RequestResponse<NetworkCodec>,
+    rendezvous: rendezvous::client::Behaviour,
 }
 
 #[derive(Debug)]
 enum OutEvent {
-    Floodsub(FloodsubEvent),
+    Gossipsub(GossipsubEvent),
     Ping(PingEvent),
+    Mdns(MdnsEvent),
+    RequestResponse(RequestResponseEvent<FileRequest, FileResponse>),
+    Rendezvous(rendezvous::client::Event),
 }
 
 impl From<PingEvent> for OutEvent {
@@ -36,9 +100,27 @@ impl From<PingEvent> for OutEvent {
     }
 }
 
-impl From<FloodsubEvent> for OutEvent {
-    fn from(v: FloodsubEvent) -> Self {
-        Self::Floodsub(v)
+impl From<GossipsubEvent> for OutEvent {
+    fn from(v: GossipsubEvent) -> Self {
+        Self::Gossipsub(v)
+    }
+}
+
+impl From<MdnsEvent> for OutEvent {
+    fn from(v: MdnsEvent) -> Self {
+        Self::Mdns(v)
+    }
+}
+
+impl From<RequestResponseEvent<FileRequest, FileResponse>> for OutEvent {
+    fn from(v: RequestResponseEvent<FileRequest, FileResponse>) -> Self {
+        Self::RequestResponse(v)
+    }
+}
+
+impl From<rendezvous::client::Event> for OutEvent {
+    fn from(v: rendezvous::client::Event) -> Self {
+        Self::Rendezvous(v)
     }
 }
 
@@ -46,33 +128,94 @@ impl From<FloodsubEvent> for OutEvent {
 async fn main() -> Result<(), Box<dyn Error>> {
     pretty_env_logger::init();
 
-    let local_key = identity::Keypair::generate_ed25519();
+    let args = Args::parse();
+
+    let local_key = load_or_generate_identity(&args.identity)?;
     let local_peer_id = PeerId::from(local_key.public());
 
+    let rendezvous_peer_id = args.rendezvous.as_ref().and_then(peer_id_from_multiaddr);
+
     let mut connection_established = false;
 
     info!("Hello, Welcome to Chakra. Enter address of a person or wait for another person to establish connection with you.");
     info!("Your peer id is: {}", local_peer_id);
 
-    let transport = libp2p::development_transport(local_key).await?;
+    let transport = libp2p::development_transport(local_key.clone()).await?;
+    let (transport, bandwidth_sinks) = BandwidthLogging::new(transport);
+    let transport = transport.boxed();
+
+    let topic = Topic::new(args.topic.clone());
+
+    let message_id_fn = |message: &GossipsubMessage| {
+        let mut hasher = DefaultHasher::new();
+        message.data.hash(&mut hasher);
+        MessageId::from(hasher.finish().to_string())
+    };
+
+    let gossipsub_config = GossipsubConfigBuilder::default()
+        .validation_mode(ValidationMode::Strict)
+        .message_id_fn(message_id_fn)
+        .build()
+        .expect("Valid gossipsub configuration");
 
-    let topic = Topic::new("chakra-chat");
-    let mut floodsub = Floodsub::new(local_peer_id);
+    let mut gossipsub =
+        Gossipsub::new(MessageAuthenticity::Signed(local_key.clone()), gossipsub_config)
+            .expect("Correct gossipsub configuration");
 
-    if !floodsub.subscribe(topic.clone()) {
-        error!("Cannot subscribe to floodsub topic! Try again later.");
+    if gossipsub.subscribe(&topic).is_err() {
+        error!("Cannot subscribe to gossipsub topic! Try again later.");
         process::exit(1);
     }
 
+    let mdns = Mdns::new(MdnsConfig::default()).await?;
+
+    let request_response = RequestResponse::new(
+        NetworkCodec::default(),
+        iter::once((FileExchangeProtocol, ProtocolSupport::Full)),
+        Default::default(),
+    );
+
+    fs::create_dir_all(DOWNLOADS_DIR)?;
+    fs::create_dir_all(SHARED_DIR)?;
+
     let behaviour = MyBehaviour {
         ping: Ping::new(PingConfig::new().with_keep_alive(true)),
-        floodsub,
+        gossipsub,
+        mdns,
+        request_response,
+        rendezvous: rendezvous::client::Behaviour::new(local_key),
+    };
+
+    let connection_limits = ConnectionLimits::default().with_max_established(args.max_peers);
+
+    let mut swarm = SwarmBuilder::new(transport, behaviour, local_peer_id)
+        .connection_limits(connection_limits)
+        .build();
+
+    let listen_addresses = if args.listen.is_empty() {
+        vec![DEFAULT_LISTEN_ADDRESS.parse()?]
+    } else {
+        args.listen.clone()
     };
 
-    let mut swarm = Swarm::new(transport, behaviour, local_peer_id);
-    swarm.listen_on(ADDRESS.parse()?)?;
+    for addr in listen_addresses {
+        swarm.listen_on(addr)?;
+    }
+
+    if let Some(addr) = &args.rendezvous {
+        swarm.dial(addr.clone())?;
+        info!(
+            "Connecting to rendezvous point {} to register under namespace '{}'",
+            addr,
+            args.namespace.as_deref().unwrap_or("")
+        );
+    }
+
+    let mut discover_tick = async_std::stream::interval(RENDEZVOUS_DISCOVER_INTERVAL).fuse();
 
     let mut stdin = io::BufReader::new(io::stdin()).lines().fuse();
+    let mut pending_downloads: HashMap<RequestId, String> = HashMap::new();
+    let mut latest_rtt: HashMap<PeerId, Duration> = HashMap::new();
 
     loop {
         select! {
@@ -85,6 +228,37 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     },
                 };
 
+                if line == "/stats" {
+                    info!(
+                        "Bandwidth so far: {} bytes in / {} bytes out",
+                        bandwidth_sinks.total_inbound(),
+                        bandwidth_sinks.total_outbound(),
+                    );
+
+                    for (peer, rtt) in &latest_rtt {
+                        info!("Latest RTT with {}: {:?}", peer, rtt);
+                    }
+                    continue;
+                }
+
+                if let Some(rest) = line.strip_prefix("/send ") {
+                    let mut parts = rest.splitn(2, ' ');
+
+                    match (parts.next().map(str::parse::<PeerId>), parts.next()) {
+                        (Some(Ok(peer_id)), Some(filename)) => {
+                            let request_id = swarm.behaviour_mut().request_response.send_request(
+                                &peer_id,
+                                FileRequest { filename: filename.to_owned() },
+                            );
+                            pending_downloads.insert(request_id, filename.to_owned());
+                            info!("Requested {} from {}", filename, peer_id);
+                        }
+                        (Some(Err(_)), _) => warn!("Entered peer id is not valid, try again!"),
+                        _ => warn!("Usage: /send <peerid> <filename>"),
+                    }
+                    continue;
+                }
+
                 if !connection_established {
                     let addr: Multiaddr = match line.parse() {
                         Ok(addr) => addr,
@@ -94,47 +268,220 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         },
                     };
 
-                    swarm.dial(addr)?;
+                    if let Err(err) = swarm.dial(addr) {
+                        warn!("Could not dial {}: {}", line, err);
+                        continue;
+                    }
                     info!("You will get notice once connection is established.");
                     continue;
                 }
 
                 print_prompt(local_peer_id).await;
 
-                swarm
+                match swarm
                     .behaviour_mut()
-                    .floodsub
-                    .publish(topic.clone(), line.as_bytes());
+                    .gossipsub
+                    .publish(topic.clone(), line.as_bytes())
+                {
+                    Ok(_) => {}
+                    Err(PublishError::InsufficientPeers) => {
+                        warn!("No peers to publish to yet, try again once connected.");
+                    }
+                    Err(err) => {
+                        error!("Failed to publish message: {:?}", err);
+                    }
+                }
+            },
+            _ = discover_tick.next() => {
+                if let (Some(namespace), Some(rendezvous_peer_id)) =
+                    (&args.namespace, rendezvous_peer_id)
+                {
+                    swarm.behaviour_mut().rendezvous.discover(
+                        Some(rendezvous::Namespace::new(namespace.clone())?),
+                        None,
+                        None,
+                        rendezvous_peer_id,
+                    );
+                }
             },
             event = swarm.select_next_some() => match event {
                 SwarmEvent::NewListenAddr { address, .. } => {
                     info!("One of your possible addresses is {}", address)
                 }
-                SwarmEvent::Behaviour(OutEvent::Ping(PingEvent { peer, .. })) => {
+                SwarmEvent::ConnectionEstablished { peer_id, .. }
+                    if Some(peer_id) == rendezvous_peer_id =>
+                {
+                    if let Some(namespace) = &args.namespace {
+                        swarm.behaviour_mut().rendezvous.register(
+                            rendezvous::Namespace::new(namespace.clone())?,
+                            peer_id,
+                            None,
+                        );
+                        info!("Registering with rendezvous point {}", peer_id);
+                    }
+                }
+                SwarmEvent::Behaviour(OutEvent::Ping(PingEvent { peer, result })) => {
                     if !connection_established {
-                        swarm
-                            .behaviour_mut()
-                            .floodsub
-                            .add_node_to_partial_view(peer);
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
 
                         info!("Connection established with {}", peer);
                         connection_established = true;
                     }
+
+                    if let Ok(PingSuccess::Ping { rtt }) = result {
+                        latest_rtt.insert(peer, rtt);
+                    }
                 },
-                SwarmEvent::Behaviour(OutEvent::Floodsub(FloodsubEvent::Subscribed { peer_id, .. })) => {
+                SwarmEvent::Behaviour(OutEvent::Gossipsub(GossipsubEvent::Subscribed { peer_id, .. })) => {
                     info!("Chat started with peer {}. Say something!", peer_id);
                     print_prompt(local_peer_id).await;
                 },
-                SwarmEvent::Behaviour(OutEvent::Floodsub(FloodsubEvent::Message(FloodsubMessage { data, source, .. }))) => {
-                    println!("\r{}{}", format_prompt(source, color::Magenta), str::from_utf8(&data).unwrap());
+                SwarmEvent::Behaviour(OutEvent::Gossipsub(GossipsubEvent::Message { propagation_source, message, .. })) => {
+                    let source = message.source.unwrap_or(propagation_source);
+                    println!("\r{}{}", format_prompt(source, color::Magenta), String::from_utf8_lossy(&message.data));
                     print_prompt(local_peer_id).await;
                 }
+                SwarmEvent::Behaviour(OutEvent::Mdns(MdnsEvent::Discovered(peers))) => {
+                    for (peer, addr) in peers {
+                        if let Err(err) = swarm.dial(addr) {
+                            warn!("Could not dial mDNS peer {}: {}", peer, err);
+                            continue;
+                        }
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                        info!("Discovered peer {} via mDNS", peer);
+                    }
+                }
+                SwarmEvent::Behaviour(OutEvent::Mdns(MdnsEvent::Expired(peers))) => {
+                    for (peer, _addr) in peers {
+                        if !swarm.behaviour().mdns.has_node(&peer) {
+                            swarm.behaviour_mut().gossipsub.remove_explicit_peer(&peer);
+                            info!("mDNS peer {} expired", peer);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(OutEvent::RequestResponse(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Request { request, channel, .. },
+                })) => {
+                    let response = match shared_file_path(&request.filename) {
+                        Some(path) => match fs::read(&path) {
+                            Ok(bytes) => FileResponse { found: true, bytes },
+                            Err(err) => {
+                                warn!("Could not read requested file {}: {}", request.filename, err);
+                                FileResponse { found: false, bytes: Vec::new() }
+                            }
+                        },
+                        None => {
+                            warn!(
+                                "Rejected file request for '{}' from {}: not a plain filename in {}",
+                                request.filename, peer, SHARED_DIR
+                            );
+                            FileResponse { found: false, bytes: Vec::new() }
+                        }
+                    };
+
+                    if swarm
+                        .behaviour_mut()
+                        .request_response
+                        .send_response(channel, response)
+                        .is_err()
+                    {
+                        warn!("Failed to send file response to {}", peer);
+                    }
+                }
+                SwarmEvent::Behaviour(OutEvent::RequestResponse(RequestResponseEvent::Message {
+                    peer,
+                    message: RequestResponseMessage::Response { request_id, response },
+                })) => {
+                    if let Some(filename) = pending_downloads.remove(&request_id) {
+                        if !response.found {
+                            warn!("Peer {} does not have file '{}'", peer, filename);
+                            continue;
+                        }
+
+                        let path = Path::new(DOWNLOADS_DIR).join(&filename);
+
+                        match fs::write(&path, &response.bytes) {
+                            Ok(_) => info!(
+                                "Saved {} ({} bytes) received from {}",
+                                path.display(),
+                                response.bytes.len(),
+                                peer
+                            ),
+                            Err(err) => error!("Could not save {}: {}", path.display(), err),
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(OutEvent::RequestResponse(RequestResponseEvent::OutboundFailure {
+                    peer,
+                    error,
+                    ..
+                })) => {
+                    warn!("File request to {} failed: {:?}", peer, error);
+                }
+                SwarmEvent::Behaviour(OutEvent::Rendezvous(rendezvous::client::Event::Discovered {
+                    registrations,
+                    ..
+                })) => {
+                    for registration in registrations {
+                        let peer = registration.record.peer_id();
+
+                        for addr in registration.record.addresses() {
+                            if let Err(err) = swarm.dial(addr.clone()) {
+                                warn!("Could not dial rendezvous peer {} at {}: {}", peer, addr, err);
+                            }
+                        }
+
+                        swarm.behaviour_mut().gossipsub.add_explicit_peer(&peer);
+                        info!("Discovered peer {} via rendezvous point", peer);
+                    }
+                }
+                SwarmEvent::Behaviour(OutEvent::Rendezvous(rendezvous::client::Event::RegisterFailed(error))) => {
+                    error!("Failed to register with rendezvous point: {:?}", error);
+                }
                 _ => {}
             }
         }
     }
 }
 
+/// Resolves a peer-supplied filename to a path inside [`SHARED_DIR`], rejecting
+/// anything that could escape it (path separators, `..` components).
+fn shared_file_path(filename: &str) -> Option<PathBuf> {
+    let name = Path::new(filename);
+
+    if filename.is_empty() || name.components().count() != 1 {
+        return None;
+    }
+
+    match name.components().next() {
+        Some(std::path::Component::Normal(_)) => Some(Path::new(SHARED_DIR).join(name)),
+        _ => None,
+    }
+}
+
+fn peer_id_from_multiaddr(addr: &Multiaddr) -> Option<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+}
+
+fn load_or_generate_identity(path: &Path) -> Result<identity::Keypair, Box<dyn Error>> {
+    if path.exists() {
+        let bytes = fs::read(path)?;
+        let keypair = identity::Keypair::from_protobuf_encoding(&bytes)?;
+        info!("Loaded existing identity from {}", path.display());
+        return Ok(keypair);
+    }
+
+    let keypair = identity::Keypair::generate_ed25519();
+    fs::write(path, keypair.to_protobuf_encoding()?)?;
+    info!("Generated new identity and saved it to {}", path.display());
+
+    Ok(keypair)
+}
+
 fn format_prompt<T>(peer_id: PeerId, color: T) -> String
 where
     T: color::Color,